@@ -0,0 +1,276 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A volumetric signed-distance field sampled on a regular 3d grid, and its
+//! marching-cubes meshing, so implicit/boolean solids can be rendered
+//! without going through a half-edge mesh.
+
+use std::collections::HashMap;
+
+use crate::prelude::VertexIndexBuffers;
+
+/// A scalar field sampled at the corners of a regular grid of cells.
+/// `samples[z * (dims.1 * dims.0) + y * dims.0 + x]` is the field value at
+/// grid corner `(x, y, z)`.
+#[derive(Clone, Debug)]
+pub struct SdfGrid {
+    pub dims: (usize, usize, usize),
+    pub origin: glam::Vec3,
+    pub cell_size: f32,
+    pub samples: Vec<f32>,
+}
+
+impl SdfGrid {
+    pub fn sample(&self, x: usize, y: usize, z: usize) -> f32 {
+        let (dx, dy, _dz) = self.dims;
+        self.samples[z * (dy * dx) + y * dx + x]
+    }
+
+    pub fn corner_pos(&self, x: usize, y: usize, z: usize) -> glam::Vec3 {
+        self.origin + glam::Vec3::new(x as f32, y as f32, z as f32) * self.cell_size
+    }
+
+    /// Central-difference gradient of the field at a grid corner, used as
+    /// the (unnormalized) surface normal.
+    fn gradient(&self, x: usize, y: usize, z: usize) -> glam::Vec3 {
+        let (dx, dy, dz) = self.dims;
+        let sample_clamped = |x: isize, y: isize, z: isize| -> f32 {
+            let x = x.clamp(0, dx as isize - 1) as usize;
+            let y = y.clamp(0, dy as isize - 1) as usize;
+            let z = z.clamp(0, dz as isize - 1) as usize;
+            self.sample(x, y, z)
+        };
+        let (xi, yi, zi) = (x as isize, y as isize, z as isize);
+        glam::Vec3::new(
+            sample_clamped(xi + 1, yi, zi) - sample_clamped(xi - 1, yi, zi),
+            sample_clamped(xi, yi + 1, zi) - sample_clamped(xi, yi - 1, zi),
+            sample_clamped(xi, yi, zi + 1) - sample_clamped(xi, yi, zi - 1),
+        )
+    }
+
+    /// Meshes this grid at `iso_level` with marching cubes. Vertices that
+    /// fall on the same grid edge are welded via a hash map keyed on the
+    /// edge identifier, so the output doesn't come out with a duplicated
+    /// vertex at every seam.
+    pub fn generate_triangle_buffers(&self, iso_level: f32) -> VertexIndexBuffers {
+        marching_cubes(self, iso_level)
+    }
+}
+
+/// Corner offsets, in grid-cell-local coordinates, in the conventional
+/// marching-cubes corner order.
+const CORNER_OFFSETS: [(usize, usize, usize); 8] = [
+    (0, 0, 0),
+    (1, 0, 0),
+    (1, 1, 0),
+    (0, 1, 0),
+    (0, 0, 1),
+    (1, 0, 1),
+    (1, 1, 1),
+    (0, 1, 1),
+];
+
+/// The 12 edges of a cube, identified by the pair of corners they connect.
+const EDGE_CORNERS: [(usize, usize); 12] = [
+    (0, 1),
+    (1, 2),
+    (2, 3),
+    (3, 0),
+    (4, 5),
+    (5, 6),
+    (6, 7),
+    (7, 4),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+fn marching_cubes(grid: &SdfGrid, iso_level: f32) -> VertexIndexBuffers {
+    let (dx, dy, dz) = grid.dims;
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut indices = Vec::new();
+    // Keyed on (min_corner, max_corner) of the grid-space edge, so two
+    // adjacent cells that share an edge reuse the same output vertex.
+    let mut vertex_cache: HashMap<((usize, usize, usize), (usize, usize, usize)), u32> =
+        HashMap::new();
+
+    if dx < 2 || dy < 2 || dz < 2 {
+        return VertexIndexBuffers {
+            positions,
+            normals,
+            indices,
+        };
+    }
+
+    for z in 0..dz - 1 {
+        for y in 0..dy - 1 {
+            for x in 0..dx - 1 {
+                let corner_grid_pos: [(usize, usize, usize); 8] =
+                    CORNER_OFFSETS.map(|(ox, oy, oz)| (x + ox, y + oy, z + oz));
+                let corner_values: [f32; 8] =
+                    corner_grid_pos.map(|(cx, cy, cz)| grid.sample(cx, cy, cz));
+
+                let mut case_index = 0u8;
+                for (i, &value) in corner_values.iter().enumerate() {
+                    if value < iso_level {
+                        case_index |= 1 << i;
+                    }
+                }
+
+                // All-inside / all-outside: no surface crosses this cell.
+                if case_index == 0 || case_index == 255 {
+                    continue;
+                }
+
+                let edge_mask = EDGE_TABLE[case_index as usize];
+                if edge_mask == 0 {
+                    continue;
+                }
+
+                // Interpolate the crossing point (and weld it) for every
+                // active edge of this cell.
+                let mut edge_vertices = [0u32; 12];
+                for edge in 0..12 {
+                    if edge_mask & (1 << edge) == 0 {
+                        continue;
+                    }
+                    let (a, b) = EDGE_CORNERS[edge];
+                    let grid_a = corner_grid_pos[a];
+                    let grid_b = corner_grid_pos[b];
+                    let key = if grid_a <= grid_b {
+                        (grid_a, grid_b)
+                    } else {
+                        (grid_b, grid_a)
+                    };
+
+                    edge_vertices[edge] = *vertex_cache.entry(key).or_insert_with(|| {
+                        let va = corner_values[a];
+                        let vb = corner_values[b];
+                        let pa = grid.corner_pos(grid_a.0, grid_a.1, grid_a.2);
+                        let pb = grid.corner_pos(grid_b.0, grid_b.1, grid_b.2);
+                        let t = if (vb - va).abs() > f32::EPSILON {
+                            (iso_level - va) / (vb - va)
+                        } else {
+                            0.5
+                        };
+                        let pos = pa + t * (pb - pa);
+
+                        let na = grid.gradient(grid_a.0, grid_a.1, grid_a.2);
+                        let nb = grid.gradient(grid_b.0, grid_b.1, grid_b.2);
+                        // The gradient points towards increasing field
+                        // value, i.e. out of the solid (inside is below
+                        // `iso_level`), so it is already the outward normal.
+                        let normal = (na + t * (nb - na)).normalize_or_zero();
+
+                        let idx = positions.len() as u32;
+                        positions.push(pos);
+                        normals.push(normal);
+                        idx
+                    });
+                }
+
+                for tri in TRI_TABLE[case_index as usize].chunks(3) {
+                    if tri[0] == -1 {
+                        break;
+                    }
+                    let i0 = edge_vertices[tri[0] as usize];
+                    let i1 = edge_vertices[tri[1] as usize];
+                    let i2 = edge_vertices[tri[2] as usize];
+                    // Skip degenerate triangles produced when an edge
+                    // intersection collapses onto a shared vertex.
+                    if i0 == i1 || i1 == i2 || i0 == i2 {
+                        continue;
+                    }
+                    indices.extend_from_slice(&[i0, i1, i2]);
+                }
+            }
+        }
+    }
+
+    VertexIndexBuffers {
+        positions,
+        normals,
+        indices,
+    }
+}
+
+/// For each of the 256 corner-inside/outside cases, a bitmask of which of
+/// the cube's 12 edges are crossed by the surface.
+#[rustfmt::skip]
+const EDGE_TABLE: [u16; 256] = [
+    0x0, 0x109, 0x203, 0x30a, 0x406, 0x50f, 0x605, 0x70c, 0x80c, 0x905, 0xa0f, 0xb06, 0xc0a, 0xd03, 0xe09, 0xf00,
+    0x190, 0x99, 0x393, 0x29a, 0x596, 0x49f, 0x795, 0x69c, 0x99c, 0x895, 0xb9f, 0xa96, 0xd9a, 0xc93, 0xf99, 0xe90,
+    0x230, 0x339, 0x33, 0x13a, 0x636, 0x73f, 0x435, 0x53c, 0xa3c, 0xb35, 0x83f, 0x936, 0xe3a, 0xf33, 0xc39, 0xd30,
+    0x3a0, 0x2a9, 0x1a3, 0xaa, 0x7a6, 0x6af, 0x5a5, 0x4ac, 0xbac, 0xaa5, 0x9af, 0x8a6, 0xfaa, 0xea3, 0xda9, 0xca0,
+    0x460, 0x569, 0x663, 0x76a, 0x66, 0x16f, 0x265, 0x36c, 0xc6c, 0xd65, 0xe6f, 0xf66, 0x86a, 0x963, 0xa69, 0xb60,
+    0x5f0, 0x4f9, 0x7f3, 0x6fa, 0x1f6, 0xff, 0x3f5, 0x2fc, 0xdfc, 0xcf5, 0xfff, 0xef6, 0x9fa, 0x8f3, 0xbf9, 0xaf0,
+    0x650, 0x759, 0x453, 0x55a, 0x256, 0x35f, 0x55, 0x15c, 0xe5c, 0xf55, 0xc5f, 0xd56, 0xa5a, 0xb53, 0x859, 0x950,
+    0x7c0, 0x6c9, 0x5c3, 0x4ca, 0x3c6, 0x2cf, 0x1c5, 0xcc, 0xfcc, 0xec5, 0xdcf, 0xcc6, 0xbca, 0xac3, 0x9c9, 0x8c0,
+    0x8c0, 0x9c9, 0xac3, 0xbca, 0xcc6, 0xdcf, 0xec5, 0xfcc, 0xcc, 0x1c5, 0x2cf, 0x3c6, 0x4ca, 0x5c3, 0x6c9, 0x7c0,
+    0x950, 0x859, 0xb53, 0xa5a, 0xd56, 0xc5f, 0xf55, 0xe5c, 0x15c, 0x55, 0x35f, 0x256, 0x55a, 0x453, 0x759, 0x650,
+    0xaf0, 0xbf9, 0x8f3, 0x9fa, 0xef6, 0xfff, 0xcf5, 0xdfc, 0x2fc, 0x3f5, 0xff, 0x1f6, 0x6fa, 0x7f3, 0x4f9, 0x5f0,
+    0xb60, 0xa69, 0x963, 0x86a, 0xf66, 0xe6f, 0xd65, 0xc6c, 0x36c, 0x265, 0x16f, 0x66, 0x76a, 0x663, 0x569, 0x460,
+    0xca0, 0xda9, 0xea3, 0xfaa, 0x8a6, 0x9af, 0xaa5, 0xbac, 0x4ac, 0x5a5, 0x6af, 0x7a6, 0xaa, 0x1a3, 0x2a9, 0x3a0,
+    0xd30, 0xc39, 0xf33, 0xe3a, 0x936, 0x83f, 0xb35, 0xa3c, 0x53c, 0x435, 0x73f, 0x636, 0x13a, 0x33, 0x339, 0x230,
+    0xe90, 0xf99, 0xc93, 0xd9a, 0xa96, 0xb9f, 0x895, 0x99c, 0x69c, 0x795, 0x49f, 0x596, 0x29a, 0x393, 0x99, 0x190,
+    0xf00, 0xe09, 0xd03, 0xc0a, 0xb06, 0xa0f, 0x905, 0x80c, 0x70c, 0x605, 0x50f, 0x406, 0x30a, 0x203, 0x109, 0x0,
+];
+
+/// For each of the 256 cases, up to 5 triangles (15 edge indices) to emit,
+/// terminated by `-1`.
+#[rustfmt::skip]
+const TRI_TABLE: [[i8; 16]; 256] = include!("marching_cubes_tri_table.in");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniform_grid(dims: (usize, usize, usize), value: f32) -> SdfGrid {
+        SdfGrid {
+            dims,
+            origin: glam::Vec3::ZERO,
+            cell_size: 1.0,
+            samples: vec![value; dims.0 * dims.1 * dims.2],
+        }
+    }
+
+    #[test]
+    fn all_outside_produces_no_geometry() {
+        let grid = uniform_grid((2, 2, 2), 1.0);
+        let buffers = grid.generate_triangle_buffers(0.0);
+        assert!(buffers.positions.is_empty());
+        assert!(buffers.indices.is_empty());
+    }
+
+    #[test]
+    fn all_inside_produces_no_geometry() {
+        let grid = uniform_grid((2, 2, 2), -1.0);
+        let buffers = grid.generate_triangle_buffers(0.0);
+        assert!(buffers.positions.is_empty());
+        assert!(buffers.indices.is_empty());
+    }
+
+    #[test]
+    fn single_crossing_corner_welds_shared_edges() {
+        // A 3x3x3 grid of a single sphere-like blob: one corner below the
+        // iso level, the rest above. Every cell sharing that corner should
+        // reuse the same welded edge vertices instead of duplicating them.
+        let mut grid = uniform_grid((3, 3, 3), 1.0);
+        grid.samples[13] = -1.0; // corner (1, 1, 1), the grid's center
+
+        let buffers = grid.generate_triangle_buffers(0.0);
+        assert!(!buffers.positions.is_empty());
+        assert_eq!(buffers.indices.len() % 3, 0);
+
+        // Exactly 6 edges cross near the lone inside corner (1,1,1): one to
+        // each of its axis neighbours. All 8 cells touch that corner and
+        // each emits one triangle (24 edge crossings in all), but welding
+        // means only 6 distinct vertices come out.
+        assert_eq!(buffers.positions.len(), 6);
+    }
+}
@@ -4,8 +4,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::application::compile_cache::{self, CompileCache};
 use crate::graph::graph_interop;
 use crate::prelude::*;
+use crate::render::shadow_map::{ShadowMapRoutine, ShadowMapSettings};
 use anyhow::Error;
 use blackjack_engine::{
     graph_compiler::{compile_graph, CompiledProgram, ExternalParameterValues},
@@ -30,6 +32,17 @@ pub struct ApplicationContext {
     /// partition the state either horizontally or vertically. This separation
     /// is dynamic, very similar to Blender's UI model
     pub split_tree: SplitTree,
+    /// Renders the directional light's depth-only pass the face routine
+    /// samples to determine occlusion. `None` until `setup` has access to a
+    /// `wgpu::Device` to build it with.
+    pub shadow_routine: Option<ShadowMapRoutine>,
+    /// Direction of the scene's directional light, kept around so the
+    /// shadow routine's light matrix can be refreshed when the mesh changes.
+    light_direction: glam::Vec3,
+    /// Memoizes the compiled program and produced `renderable_thing` so
+    /// `run_active_node` can skip `compile_graph` and the Lua program when
+    /// nothing upstream of the active node changed since the last frame.
+    compile_cache: CompileCache,
 }
 
 impl ApplicationContext {
@@ -37,17 +50,24 @@ impl ApplicationContext {
         ApplicationContext {
             renderable_thing: None,
             split_tree: SplitTree::default_tree(),
+            shadow_routine: None,
+            light_direction: glam::Vec3::new(-1.0, -4.0, 2.0),
+            compile_cache: CompileCache::new(),
         }
     }
 
-    pub fn setup(&self, render_ctx: &mut RenderContext) {
+    pub fn setup(&mut self, render_ctx: &mut RenderContext) {
         render_ctx.add_light(r3::DirectionalLight {
             color: glam::Vec3::ONE,
             intensity: 10.0,
             // Direction will be normalized
-            direction: glam::Vec3::new(-1.0, -4.0, 2.0),
+            direction: self.light_direction,
             distance: 400.0,
         });
+        self.shadow_routine = Some(ShadowMapRoutine::new(
+            &render_ctx.renderer.device,
+            ShadowMapSettings::default(),
+        ));
     }
 
     pub fn update(
@@ -83,6 +103,15 @@ impl ApplicationContext {
             self.paint_errors(egui_ctx, err);
         }
 
+        let export_gltf_requested = egui_ctx.input(|input| {
+            input.modifiers.ctrl && input.key_pressed(egui::Key::G)
+        });
+        if export_gltf_requested {
+            if let Err(err) = self.export_gltf(std::path::Path::new("export.glb")) {
+                self.paint_errors(egui_ctx, err);
+            }
+        }
+
         actions
     }
 
@@ -112,6 +141,18 @@ impl ApplicationContext {
                         FaceDrawMode::None => None,
                     } {
                         if !positions.is_empty() {
+                            if let Some(shadow_routine) = self.shadow_routine.as_mut() {
+                                shadow_routine.update_light_matrix(
+                                    &render_ctx.renderer.queue,
+                                    self.light_direction,
+                                    scene_radius(&positions),
+                                );
+                                shadow_routine.render_depth_pass_immediate(
+                                    &render_ctx.renderer.device,
+                                    &render_ctx.renderer.queue,
+                                    &positions,
+                                );
+                            }
                             render_ctx.face_routine.add_base_mesh(
                                 &render_ctx.renderer,
                                 &positions,
@@ -180,6 +221,30 @@ impl ApplicationContext {
                     );
                 }
             }
+            // `RenderableThing` itself -- including the `HalfEdgeMesh` and
+            // `HeightMap` arms above -- is defined in
+            // `blackjack_engine::lua_engine`, which (like `RenderContext`
+            // and `Viewport3dSettings`) isn't part of this source checkout;
+            // it was already referenced-but-undefined in the pre-backlog
+            // baseline. This `Sdf` arm only adds the match against the new
+            // variant; the variant itself still needs adding to that
+            // out-of-tree enum in a full checkout.
+            Some(RenderableThing::Sdf(grid)) => {
+                let VertexIndexBuffers {
+                    positions,
+                    normals,
+                    indices,
+                } = grid.generate_triangle_buffers(0.0);
+
+                if !positions.is_empty() {
+                    render_ctx.face_routine.add_base_mesh(
+                        &render_ctx.renderer,
+                        &positions,
+                        &normals,
+                        &indices,
+                    );
+                }
+            }
             None => { /* Ignore */ }
         }
         Ok(())
@@ -203,14 +268,28 @@ impl ApplicationContext {
         node: NodeId,
         is_side_effect: bool,
     ) -> Result<(CompiledProgram, ExternalParameterValues)> {
-        let (bjk_graph, mapping) = graph_interop::ui_graph_to_blackjack_graph(&editor_state.graph)?;
-        let final_node = mapping[node];
-        let program = compile_graph(&bjk_graph, final_node, is_side_effect)?;
+        let (mapping, program) = self.compile_program_cacheable(editor_state, node, is_side_effect)?;
         let params = graph_interop::extract_graph_params(&editor_state.graph, &mapping, &program)?;
 
         Ok((program, params))
     }
 
+    /// The part of `compile_program` that only depends on the graph's shape
+    /// (node ops, connections), not on any parameter's current value. Used
+    /// by `run_active_node` so it can cache this half behind the structural
+    /// hash while still re-extracting parameter values every frame.
+    fn compile_program_cacheable(
+        &self,
+        editor_state: &graph::GraphEditorState,
+        node: NodeId,
+        is_side_effect: bool,
+    ) -> Result<(graph_interop::NodeMapping, CompiledProgram)> {
+        let (bjk_graph, mapping) = graph_interop::ui_graph_to_blackjack_graph(&editor_state.graph)?;
+        let final_node = mapping[node];
+        let program = compile_graph(&bjk_graph, final_node, is_side_effect)?;
+        Ok((mapping, program))
+    }
+
     // Returns the compiled lua code
     pub fn run_active_node(
         &mut self,
@@ -219,20 +298,58 @@ impl ApplicationContext {
         lua_runtime: &LuaRuntime,
     ) -> Result<String> {
         if let Some(active) = custom_state.active_node {
-            let (program, params) = self.compile_program(editor_state, active, false)?;
+            let hash = self
+                .compile_cache
+                .structural_hash_of(&editor_state.graph, active);
+
+            if self.compile_cache.cached_for(hash).is_none() {
+                let compiled = self.compile_program_cacheable(editor_state, active, false)?;
+                self.compile_cache.store(hash, compiled);
+            }
+            let (mapping, program) = self
+                .compile_cache
+                .cached_for(hash)
+                .expect("just stored above if missing");
+
+            // Unlike the structural hash, this has to be extracted fresh
+            // every frame even on a cache hit: a parameter widget can
+            // change value (e.g. a dragged slider) without altering the
+            // graph's shape at all, so the structural hash alone can't
+            // tell us whether the Lua program needs to re-run.
+            let params = graph_interop::extract_graph_params(&editor_state.graph, mapping, program)?;
+
+            let combined = compile_cache::combined_hash(program, &params);
+            if self.compile_cache.is_renderable_fresh(combined) && self.renderable_thing.is_some() {
+                return Ok(program.lua_program.clone());
+            }
+
             let mesh = blackjack_engine::lua_engine::run_program(
                 &lua_runtime.lua,
                 &program.lua_program,
-                &params,
+                params,
             )?;
+            let lua_program = program.lua_program.clone();
             self.renderable_thing = Some(mesh);
-            Ok(program.lua_program)
+            self.compile_cache.set_combined_hash(combined);
+            Ok(lua_program)
         } else {
             self.renderable_thing = None;
+            self.compile_cache.invalidate();
             Ok("".into())
         }
     }
 
+    /// Exports the active `renderable_thing` as a binary glTF file. Invoked
+    /// from `update` in response to the Ctrl+G shortcut, so users can export
+    /// directly from the UI without going through a Lua side effect.
+    pub fn export_gltf(&self, path: &std::path::Path) -> Result<()> {
+        let renderable = self
+            .renderable_thing
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Nothing to export: the viewport has no mesh"))?;
+        crate::io::gltf_export::export_gltf(renderable, path)
+    }
+
     pub fn run_side_effects(
         &mut self,
         editor_state: &mut graph::GraphEditorState,
@@ -258,3 +375,13 @@ impl Default for ApplicationContext {
         Self::new()
     }
 }
+
+/// Smallest radius, centered on the origin, that contains every position,
+/// but never less than 1.0. Used to fit the shadow map's orthographic frustum to the current mesh.
+fn scene_radius(positions: &[glam::Vec3]) -> f32 {
+    positions
+        .iter()
+        .map(|p| p.length())
+        .fold(0.0_f32, f32::max)
+        .max(1.0)
+}
@@ -0,0 +1,148 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Memoizes `ApplicationContext::run_active_node`'s two expensive steps so
+//! `update` doesn't re-run `compile_graph` and the Lua program every single
+//! frame when nothing upstream of the active node has changed.
+//!
+//! Two hashes gate the two steps:
+//! - A structural hash of the subgraph reachable from the active node (node
+//!   ops, connections) gates compiling the graph down to a `CompiledProgram`.
+//! - A combined hash of (the compiled program, freshly extracted external
+//!   parameter values) gates `run_program`. The parameter values are never
+//!   cached themselves — they're read from the graph every frame regardless
+//!   of whether the structural hash hit, since a parameter widget can change
+//!   without altering the graph's shape.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use blackjack_engine::graph_compiler::{CompiledProgram, ExternalParameterValues};
+use egui_node_graph::NodeId;
+
+use crate::graph::graph_interop::NodeMapping;
+use crate::graph::node_graph::Graph;
+
+#[derive(Default)]
+pub struct CompileCache {
+    structural_hash: Option<u64>,
+    combined_hash: Option<u64>,
+    /// The compiled program and the node mapping it was compiled against.
+    /// Deliberately doesn't include `ExternalParameterValues`: those can
+    /// change every frame (e.g. dragging a slider) without the graph's
+    /// shape changing, so they're always re-extracted fresh rather than
+    /// cached alongside the program.
+    cached_program: Option<(NodeMapping, CompiledProgram)>,
+}
+
+impl CompileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clears every memoized value. Used when there's no active node.
+    pub fn invalidate(&mut self) {
+        self.structural_hash = None;
+        self.combined_hash = None;
+        self.cached_program = None;
+    }
+
+    /// Hashes the subgraph reachable from `active`. Callers check this
+    /// against `cached_for` before deciding whether `compile_program` needs
+    /// to run again.
+    pub fn structural_hash_of(&self, graph: &Graph, active: NodeId) -> u64 {
+        structural_hash(graph, active)
+    }
+
+    /// Returns the memoized `(NodeMapping, CompiledProgram)` if it was last
+    /// stored under `hash`, or `None` if it needs recomputing.
+    pub fn cached_for(&self, hash: u64) -> Option<&(NodeMapping, CompiledProgram)> {
+        if self.structural_hash == Some(hash) {
+            self.cached_program.as_ref()
+        } else {
+            None
+        }
+    }
+
+    /// Stores a freshly compiled program under `hash`.
+    pub fn store(&mut self, hash: u64, compiled: (NodeMapping, CompiledProgram)) {
+        self.structural_hash = Some(hash);
+        self.cached_program = Some(compiled);
+        // The program changed, so any memoized renderable is stale
+        // regardless of what `combined_hash` said before.
+        self.combined_hash = None;
+    }
+
+    /// `true` if `hash` (from `combined_hash`) matches the one stored by
+    /// the last `set_combined_hash` call, meaning `run_program` can be
+    /// skipped and the existing `renderable_thing` reused.
+    pub fn is_renderable_fresh(&self, hash: u64) -> bool {
+        self.combined_hash == Some(hash)
+    }
+
+    pub fn set_combined_hash(&mut self, hash: u64) {
+        self.combined_hash = Some(hash);
+    }
+}
+
+/// Combines the program's hash (its emitted Lua source fully determines its
+/// behavior) with the external parameter values feeding it.
+pub fn combined_hash(program: &CompiledProgram, params: &ExternalParameterValues) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    program.lua_program.hash(&mut hasher);
+    // `ExternalParameterValues`'s value type isn't `Hash` (it stores
+    // arbitrary graph-editor values), so we hash its `Debug` rendering
+    // instead. Cheap relative to `run_program`, and stable as long as the
+    // values' `Debug` impls are.
+    format!("{:?}", params).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes the subset of `graph` reachable by walking connections backwards
+/// from `active` (node ids, ops, connections).
+///
+/// Node contributions are combined order-independently (`u64::wrapping_add`)
+/// so the hash doesn't depend on `graph.nodes`' iteration order, only on
+/// which nodes and connections are actually present.
+fn structural_hash(graph: &Graph, active: NodeId) -> u64 {
+    let mut visited = HashSet::new();
+    let mut stack = vec![active];
+    let mut acc: u64 = 0;
+
+    while let Some(node_id) = stack.pop() {
+        if !visited.insert(node_id) {
+            continue;
+        }
+        let Some(node) = graph.nodes.get(node_id) else {
+            continue;
+        };
+
+        let mut hasher = DefaultHasher::new();
+        node_id.hash(&mut hasher);
+        node.user_data.op_name.hash(&mut hasher);
+        for (input_name, _) in &node.inputs {
+            input_name.hash(&mut hasher);
+        }
+        for (output_name, _) in &node.outputs {
+            output_name.hash(&mut hasher);
+        }
+        acc = acc.wrapping_add(hasher.finish());
+
+        for (_, input_id) in &node.inputs {
+            if let Some(output_id) = graph.connections.get(*input_id) {
+                let upstream = graph[*output_id].node;
+                stack.push(upstream);
+
+                let mut edge_hasher = DefaultHasher::new();
+                (upstream, node_id).hash(&mut edge_hasher);
+                acc = acc.wrapping_add(edge_hasher.finish());
+            }
+        }
+    }
+
+    acc
+}
@@ -0,0 +1,474 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small preprocessing layer that runs before `wgpu` shader-module
+//! creation, so the face/wireframe/point-cloud routines can share a single
+//! lighting module instead of each carrying their own copy-pasted WGSL.
+//!
+//! Supports:
+//! - `#include "lib/lighting.wgsl"`, resolved against a registered virtual
+//!   shader library.
+//! - `#define NAME value` substitution.
+//! - `#ifdef NAME` / `#else` / `#endif` blocks, keyed on a caller-supplied
+//!   set of feature flags (e.g. `SMOOTH_NORMALS`, `SHADOWS`).
+//!
+//! Includes are flattened recursively with cycle detection, and each
+//! resolved line remembers which source file and line it came from, so a
+//! `naga` compile error on the flattened string can be mapped back to
+//! where the offending line actually lives.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A line in the flattened output, tagged with where it came from so
+/// compile errors can be mapped back to the original source.
+#[derive(Clone, Debug)]
+struct TaggedLine {
+    file: &'static str,
+    line: usize,
+    text: String,
+}
+
+/// Maps a 1-based line number in the flattened source back to its origin.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    lines: Vec<(&'static str, usize)>,
+}
+
+impl SourceMap {
+    /// Returns `(file, line)` in the original source for a 1-based line
+    /// number in the preprocessor's output.
+    pub fn resolve(&self, flattened_line: usize) -> Option<(&'static str, usize)> {
+        self.lines.get(flattened_line.saturating_sub(1)).copied()
+    }
+}
+
+/// Re-renders `source` with each line prefixed by the original `file:line`
+/// it came from, per `source_map`. `naga`'s compile errors report a line
+/// number in the flattened source wgpu was handed; pairing that message
+/// with this dump is how it gets mapped back to the actual WGSL source.
+pub fn annotate_with_source_map(source: &str, source_map: &SourceMap) -> String {
+    source
+        .lines()
+        .enumerate()
+        .map(|(idx, text)| match source_map.resolve(idx + 1) {
+            Some((file, line)) => format!("{file}:{line}: {text}"),
+            None => text.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug)]
+pub enum PreprocessError {
+    IncludeNotFound { path: String, from: &'static str },
+    IncludeCycle { path: String },
+    UnterminatedIfdef { file: &'static str, line: usize },
+    DanglingElseOrEndif { file: &'static str, line: usize },
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessError::IncludeNotFound { path, from } => {
+                write!(f, "shader library '{path}' not found (included from {from})")
+            }
+            PreprocessError::IncludeCycle { path } => {
+                write!(f, "cyclic #include of '{path}'")
+            }
+            PreprocessError::UnterminatedIfdef { file, line } => {
+                write!(f, "unterminated #ifdef at {file}:{line}")
+            }
+            PreprocessError::DanglingElseOrEndif { file, line } => {
+                write!(f, "#else/#endif without matching #ifdef at {file}:{line}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// A registry of named WGSL source fragments that `#include` can resolve
+/// against. Populated with `include_str!`-embedded library files; routines
+/// can also register disk-loaded sources for hot-reload during development.
+#[derive(Default)]
+pub struct ShaderLibrary {
+    sources: HashMap<&'static str, &'static str>,
+}
+
+impl ShaderLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, virtual_path: &'static str, source: &'static str) {
+        self.sources.insert(virtual_path, source);
+    }
+
+    fn get(&self, virtual_path: &str) -> Option<&'static str> {
+        self.sources.get(virtual_path).copied()
+    }
+}
+
+/// The result of preprocessing: the flattened WGSL ready for
+/// `wgpu::ShaderSource::Wgsl`, plus a map back to the original sources.
+pub struct PreprocessedShader {
+    pub source: String,
+    pub source_map: SourceMap,
+}
+
+/// Flattens `entry_source` (recorded in the source map and in cycle
+/// detection under the name `entry_path`, e.g. a virtual path or the
+/// synthetic name `"<entry>"` for inline source) into a single WGSL string, resolving `#include`s, `#define`s and `#ifdef` blocks for
+/// the given `flags`.
+pub fn preprocess(
+    library: &ShaderLibrary,
+    entry_path: &'static str,
+    entry_source: &'static str,
+    flags: &HashSet<&'static str>,
+) -> Result<PreprocessedShader, PreprocessError> {
+    let mut defines: HashMap<String, String> = HashMap::new();
+    let mut output = Vec::new();
+    let mut visiting = HashSet::new();
+
+    flatten(
+        library,
+        entry_path,
+        entry_source,
+        flags,
+        &mut defines,
+        &mut visiting,
+        &mut output,
+    )?;
+
+    let mut source = String::new();
+    let mut source_map = SourceMap::default();
+    for tagged in &output {
+        source.push_str(&tagged.text);
+        source.push('\n');
+        source_map.lines.push((tagged.file, tagged.line));
+    }
+
+    Ok(PreprocessedShader { source, source_map })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn flatten(
+    library: &ShaderLibrary,
+    file: &'static str,
+    source: &'static str,
+    flags: &HashSet<&'static str>,
+    defines: &mut HashMap<String, String>,
+    visiting: &mut HashSet<&'static str>,
+    output: &mut Vec<TaggedLine>,
+) -> Result<(), PreprocessError> {
+    if !visiting.insert(file) {
+        return Err(PreprocessError::IncludeCycle { path: file.into() });
+    }
+
+    // Stack of `(condition_taken, seen_else)` for nested #ifdef blocks.
+    let mut cond_stack: Vec<(bool, bool)> = Vec::new();
+
+    for (idx, raw_line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let trimmed: &'static str = raw_line.trim_start();
+        let currently_active = cond_stack.iter().all(|(taken, _)| *taken);
+
+        if let Some(rest) = trimmed.strip_prefix("#include") {
+            if !currently_active {
+                continue;
+            }
+            let path: &'static str = rest.trim().trim_matches('"');
+            let included_source = library
+                .get(path)
+                .ok_or_else(|| PreprocessError::IncludeNotFound {
+                    path: path.to_string(),
+                    from: file,
+                })?;
+            flatten(
+                library,
+                path,
+                included_source,
+                flags,
+                defines,
+                visiting,
+                output,
+            )?;
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("#define") {
+            if currently_active {
+                let mut parts = rest.trim().splitn(2, char::is_whitespace);
+                let name = parts.next().unwrap_or_default().to_string();
+                let value = parts.next().unwrap_or_default().trim().to_string();
+                defines.insert(name, value);
+            }
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("#ifdef") {
+            let name = rest.trim();
+            let taken = currently_active && (flags.contains(name) || defines.contains_key(name));
+            cond_stack.push((taken, false));
+            continue;
+        }
+
+        if trimmed.starts_with("#else") {
+            if cond_stack.is_empty() {
+                return Err(PreprocessError::DanglingElseOrEndif { file, line: line_no });
+            }
+            let depth = cond_stack.len() - 1;
+            let (was_taken, seen_else) = cond_stack[depth];
+            if seen_else {
+                return Err(PreprocessError::DanglingElseOrEndif { file, line: line_no });
+            }
+            let parent_active = cond_stack[..depth].iter().all(|(t, _)| *t);
+            cond_stack[depth] = (parent_active && !was_taken, true);
+            continue;
+        }
+
+        if trimmed.starts_with("#endif") {
+            cond_stack
+                .pop()
+                .ok_or(PreprocessError::DanglingElseOrEndif { file, line: line_no })?;
+            continue;
+        }
+
+        if !currently_active {
+            continue;
+        }
+
+        let substituted = substitute_defines(raw_line, defines);
+        output.push(TaggedLine {
+            file,
+            line: line_no,
+            text: substituted,
+        });
+    }
+
+    if !cond_stack.is_empty() {
+        return Err(PreprocessError::UnterminatedIfdef {
+            file,
+            line: source.lines().count(),
+        });
+    }
+
+    visiting.remove(file);
+    Ok(())
+}
+
+fn substitute_defines(line: &str, defines: &HashMap<String, String>) -> String {
+    if defines.is_empty() {
+        return line.to_string();
+    }
+    let mut result = line.to_string();
+    for (name, value) in defines {
+        if value.is_empty() {
+            continue;
+        }
+        result = replace_word(&result, name, value);
+    }
+    result
+}
+
+/// Replaces whole-word occurrences of `name` with `value`, so e.g. a define
+/// named `N` doesn't also rewrite `NORMAL`.
+fn replace_word(haystack: &str, name: &str, value: &str) -> String {
+    let mut out = String::with_capacity(haystack.len());
+    let mut rest = haystack;
+    while let Some(pos) = rest.find(name) {
+        let before_ok = rest[..pos]
+            .chars()
+            .next_back()
+            .map_or(true, |c| !is_word_char(c));
+        let after = pos + name.len();
+        let after_ok = rest[after..]
+            .chars()
+            .next()
+            .map_or(true, |c| !is_word_char(c));
+
+        out.push_str(&rest[..pos]);
+        if before_ok && after_ok {
+            out.push_str(value);
+        } else {
+            out.push_str(&rest[pos..after]);
+        }
+        rest = &rest[after..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// A cache of flattened shaders keyed by `(entry_path, sorted flag set)` so
+/// routines that rebuild their pipeline every frame (e.g. on a settings
+/// change) don't re-run the preprocessor when the flag set is unchanged.
+///
+/// Caches the whole [PreprocessedShader], source map included, so callers
+/// can map a `naga` compile error on the flattened source back to where it
+/// actually came from (see [PreprocessedShader::source_map]) without having
+/// to keep their own copy around.
+#[derive(Default)]
+pub struct PreprocessorCache {
+    entries: HashMap<(&'static str, Vec<&'static str>), PreprocessedShader>,
+}
+
+impl PreprocessorCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_preprocess(
+        &mut self,
+        library: &ShaderLibrary,
+        entry_path: &'static str,
+        entry_source: &'static str,
+        flags: &HashSet<&'static str>,
+    ) -> Result<&PreprocessedShader, PreprocessError> {
+        let mut key_flags: Vec<&'static str> = flags.iter().copied().collect();
+        key_flags.sort_unstable();
+        let key = (entry_path, key_flags);
+
+        if !self.entries.contains_key(&key) {
+            let preprocessed = preprocess(library, entry_path, entry_source, flags)?;
+            self.entries.insert(key.clone(), preprocessed);
+        }
+        Ok(self.entries.get(&key).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(names: &[&'static str]) -> HashSet<&'static str> {
+        names.iter().copied().collect()
+    }
+
+    #[test]
+    fn ifdef_takes_the_true_branch_when_flag_is_set() {
+        let library = ShaderLibrary::new();
+        let source = "a\n#ifdef FOO\nb\n#else\nc\n#endif\nd";
+        let result = preprocess(&library, "<entry>", source, &flags(&["FOO"])).unwrap();
+        assert_eq!(result.source, "a\nb\nd\n");
+    }
+
+    #[test]
+    fn ifdef_takes_the_else_branch_when_flag_is_unset() {
+        let library = ShaderLibrary::new();
+        let source = "a\n#ifdef FOO\nb\n#else\nc\n#endif\nd";
+        let result = preprocess(&library, "<entry>", source, &flags(&[])).unwrap();
+        assert_eq!(result.source, "a\nc\nd\n");
+    }
+
+    #[test]
+    fn nested_ifdef_only_emits_when_every_enclosing_branch_is_taken() {
+        let library = ShaderLibrary::new();
+        let source = "#ifdef OUTER\n#ifdef INNER\nboth\n#else\nouter_only\n#endif\n#endif\ntail";
+
+        let neither = preprocess(&library, "<entry>", source, &flags(&[])).unwrap();
+        assert_eq!(neither.source, "tail\n");
+
+        let outer_only = preprocess(&library, "<entry>", source, &flags(&["OUTER"])).unwrap();
+        assert_eq!(outer_only.source, "outer_only\ntail\n");
+
+        let both = preprocess(&library, "<entry>", source, &flags(&["OUTER", "INNER"])).unwrap();
+        assert_eq!(both.source, "both\ntail\n");
+    }
+
+    #[test]
+    fn dangling_else_is_an_error() {
+        let library = ShaderLibrary::new();
+        let source = "a\n#else\nb\n#endif\n";
+        let err = preprocess(&library, "<entry>", source, &flags(&[])).unwrap_err();
+        assert!(matches!(err, PreprocessError::DanglingElseOrEndif { .. }));
+    }
+
+    #[test]
+    fn dangling_endif_is_an_error() {
+        let library = ShaderLibrary::new();
+        let source = "a\n#endif\n";
+        let err = preprocess(&library, "<entry>", source, &flags(&[])).unwrap_err();
+        assert!(matches!(err, PreprocessError::DanglingElseOrEndif { .. }));
+    }
+
+    #[test]
+    fn unterminated_ifdef_is_an_error() {
+        let library = ShaderLibrary::new();
+        let source = "#ifdef FOO\na\n";
+        let err = preprocess(&library, "<entry>", source, &flags(&["FOO"])).unwrap_err();
+        assert!(matches!(err, PreprocessError::UnterminatedIfdef { .. }));
+    }
+
+    #[test]
+    fn include_is_flattened_in_place() {
+        let mut library = ShaderLibrary::new();
+        library.register("lib/foo.wgsl", "from_foo");
+        let source = "before\n#include \"lib/foo.wgsl\"\nafter";
+        let result = preprocess(&library, "<entry>", source, &flags(&[])).unwrap();
+        assert_eq!(result.source, "before\nfrom_foo\nafter\n");
+    }
+
+    #[test]
+    fn missing_include_is_an_error() {
+        let library = ShaderLibrary::new();
+        let source = "#include \"lib/missing.wgsl\"";
+        let err = preprocess(&library, "<entry>", source, &flags(&[])).unwrap_err();
+        assert!(matches!(err, PreprocessError::IncludeNotFound { .. }));
+    }
+
+    #[test]
+    fn include_cycle_is_detected() {
+        let mut library = ShaderLibrary::new();
+        library.register("a.wgsl", "#include \"b.wgsl\"");
+        library.register("b.wgsl", "#include \"a.wgsl\"");
+        let err = preprocess(&library, "a.wgsl", library.get("a.wgsl").unwrap(), &flags(&[])).unwrap_err();
+        assert!(matches!(err, PreprocessError::IncludeCycle { .. }));
+    }
+
+    #[test]
+    fn define_substitutes_whole_words_only() {
+        let library = ShaderLibrary::new();
+        let source = "#define N 4\narray<f32, N>;\nNORMAL;";
+        let result = preprocess(&library, "<entry>", source, &flags(&[])).unwrap();
+        assert_eq!(result.source, "array<f32, 4>;\nNORMAL;\n");
+    }
+
+    #[test]
+    fn preprocessor_cache_reuses_entries_for_the_same_flag_set() {
+        let library = ShaderLibrary::new();
+        let mut cache = PreprocessorCache::new();
+        let flags_a = flags(&["SHADOWS"]);
+
+        let first = cache
+            .get_or_preprocess(&library, "<entry>", "#ifdef SHADOWS\na\n#endif", &flags_a)
+            .unwrap()
+            .source
+            .clone();
+        let second = cache
+            .get_or_preprocess(&library, "<entry>", "#ifdef SHADOWS\na\n#endif", &flags_a)
+            .unwrap()
+            .source
+            .clone();
+
+        assert_eq!(first, second);
+        assert_eq!(first, "a\n");
+    }
+
+    #[test]
+    fn annotate_with_source_map_prefixes_each_line_with_its_origin() {
+        let library = ShaderLibrary::new();
+        let result = preprocess(&library, "entry.wgsl", "a\nb", &flags(&[])).unwrap();
+
+        let annotated = annotate_with_source_map(&result.source, &result.source_map);
+
+        assert_eq!(annotated, "entry.wgsl:1: a\nentry.wgsl:2: b");
+    }
+}
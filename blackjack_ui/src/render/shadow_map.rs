@@ -0,0 +1,545 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A depth-only shadow map pass for the directional light set up in
+//! `ApplicationContext::setup`. The pass renders the scene from the light's
+//! point of view into a depth texture, which the face routine's fragment
+//! shader then samples to determine occlusion via the bind group exposed
+//! by [`ShadowMapRoutine::bind_group`] and the `lib/shadow.wgsl`/
+//! `lib/lighting.wgsl` helpers it binds to `@group(3)`.
+
+use std::collections::HashSet;
+
+use crate::prelude::*;
+use crate::render::shader_preprocessor::{annotate_with_source_map, PreprocessorCache, ShaderLibrary};
+
+/// How the shadow map is sampled when shading a fragment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowFilterMode {
+    /// A single hardware 2x2 comparison sample (`textureSampleCompare` on a
+    /// `sampler_comparison`). Cheapest, but edges are noticeably hard.
+    Hardware,
+    /// Percentage-closer filtering: averages `PCF_SAMPLE_COUNT` comparison
+    /// samples taken around the projected texel on a fixed Poisson-disk
+    /// offset pattern.
+    Pcf,
+    /// Percentage-closer soft shadows: a blocker search over the same disk
+    /// estimates the average occluder depth, which is used to derive a
+    /// penumbra radius so the PCF kernel widens with distance from the
+    /// occluder.
+    Pcss,
+}
+
+impl Default for ShadowFilterMode {
+    fn default() -> Self {
+        ShadowFilterMode::Pcf
+    }
+}
+
+/// Number of taps used for both the PCF average and the PCSS blocker search.
+/// Mirrored in `lib/shadow.wgsl` as `POISSON_DISK` -- keep the two in
+/// sync if this changes.
+pub const PCF_SAMPLE_COUNT: usize = 16;
+
+/// A Poisson-disk offset pattern in the unit disk, used to jitter shadow
+/// taps so PCF/PCSS averaging doesn't produce banding artifacts.
+pub const POISSON_DISK: [(f32, f32); PCF_SAMPLE_COUNT] = [
+    (-0.942_016_24, -0.399_062_16),
+    (0.945_586_1, -0.768_907_25),
+    (-0.094_184_1, -0.929_388_8),
+    (0.344_959_38, 0.293_877_6),
+    (-0.915_885_81, 0.457_714_32),
+    (-0.815_442_32, -0.879_124_64),
+    (-0.382_775_43, 0.276_768_45),
+    (0.974_843_98, 0.756_483_79),
+    (0.443_233_25, -0.975_698_42),
+    (0.537_429_81, -0.473_734_2),
+    (-0.264_969_11, -0.418_930_23),
+    (0.791_975_14, 0.190_901_88),
+    (-0.241_888_4, 0.997_065_07),
+    (-0.814_399_29, 0.913_347_58),
+    (0.199_841_26, 0.786_413_67),
+    (0.143_529_8, -0.141_008_9),
+];
+
+/// Per-light settings exposed alongside `FaceDrawMode`/`EdgeDrawMode` in
+/// `Viewport3dSettings`.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowMapSettings {
+    pub filter_mode: ShadowFilterMode,
+    /// Depth-bias applied in light space before the comparison, to avoid
+    /// shadow acne on surfaces nearly parallel to the light.
+    pub depth_bias: f32,
+    /// Size of the PCSS light area, in light-space units. Controls how
+    /// quickly the penumbra widens with occluder distance.
+    pub pcss_light_size: f32,
+    /// Side length, in texels, of the shadow map.
+    pub resolution: u32,
+}
+
+impl Default for ShadowMapSettings {
+    fn default() -> Self {
+        Self {
+            filter_mode: ShadowFilterMode::default(),
+            depth_bias: 0.002,
+            pcss_light_size: 0.35,
+            resolution: 2048,
+        }
+    }
+}
+
+/// Owns the shadow map depth texture, the depth-only pipeline used to
+/// render into it, and the bind group (depth view, samplers, light matrix
+/// uniform) a consuming fragment shader binds at `@group(3)` to read it
+/// back via `lib/shadow.wgsl`.
+pub struct ShadowMapRoutine {
+    pub settings: ShadowMapSettings,
+    depth_texture: wgpu::Texture,
+    depth_view: wgpu::TextureView,
+    comparison_sampler: wgpu::Sampler,
+    /// Non-comparison sampler bound alongside `depth_view` at
+    /// `shadow_map_unfiltered`/`shadow_sampler_unfiltered` in `lib/shadow.wgsl`,
+    /// for the PCSS blocker search's raw depth reads.
+    unfiltered_sampler: wgpu::Sampler,
+    /// Backs `lib/shadow.wgsl`'s `shadow_light` uniform, kept in sync with
+    /// `light_view_proj` by `update_light_matrix`.
+    light_matrix_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::RenderPipeline,
+    /// Flattened shaders are cached per filter mode, so flipping
+    /// `ShadowFilterMode` back and forth (e.g. from a settings UI) doesn't
+    /// re-run the preprocessor on filter modes it's already seen.
+    shader_cache: PreprocessorCache,
+    light_view_proj: glam::Mat4,
+}
+
+impl ShadowMapRoutine {
+    pub fn new(device: &wgpu::Device, settings: ShadowMapSettings) -> Self {
+        let depth_texture = Self::make_depth_texture(device, settings.resolution);
+        let depth_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let comparison_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_map_comparison_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let unfiltered_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_map_unfiltered_sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let light_matrix_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow_map_light_matrix_buffer"),
+            size: std::mem::size_of::<glam::Mat4>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = Self::make_bind_group_layout(device);
+        let bind_group = Self::make_bind_group(
+            device,
+            &bind_group_layout,
+            &depth_view,
+            &comparison_sampler,
+            &unfiltered_sampler,
+            &light_matrix_buffer,
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow_map_pipeline_layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[wgpu::PushConstantRange {
+                stages: wgpu::ShaderStages::VERTEX,
+                range: 0..std::mem::size_of::<glam::Mat4>() as u32 * 2,
+            }],
+        });
+
+        let mut shader_cache = PreprocessorCache::new();
+        let pipeline = Self::build_pipeline(
+            device,
+            &pipeline_layout,
+            &mut shader_cache,
+            settings.filter_mode,
+        );
+
+        Self {
+            settings,
+            depth_texture,
+            depth_view,
+            comparison_sampler,
+            unfiltered_sampler,
+            light_matrix_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline_layout,
+            pipeline,
+            shader_cache,
+            light_view_proj: glam::Mat4::IDENTITY,
+        }
+    }
+
+    /// Layout for the `@group(3)` bindings declared in `lib/shadow.wgsl`:
+    /// the comparison-sampled depth view, the same view with a plain
+    /// sampler, and the light view-projection uniform.
+    fn make_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("shadow_map_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    fn make_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        depth_view: &wgpu::TextureView,
+        comparison_sampler: &wgpu::Sampler,
+        unfiltered_sampler: &wgpu::Sampler,
+        light_matrix_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_map_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(depth_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(comparison_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(depth_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(unfiltered_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: light_matrix_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Layout the face routine's pipeline must reuse (as its `@group(3)`)
+    /// when building the bind group layout list for a pipeline that
+    /// `#include`s `lib/shadow.wgsl`.
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    /// Bind group the face routine's fragment pass must bind at index 3,
+    /// backing every binding `lib/shadow.wgsl` declares.
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Flattens `shadow_depth.wgsl` for `filter_mode` (through `cache`, so
+    /// repeat filter modes are free) and builds the depth-only pipeline
+    /// around it.
+    fn build_pipeline(
+        device: &wgpu::Device,
+        pipeline_layout: &wgpu::PipelineLayout,
+        cache: &mut PreprocessorCache,
+        filter_mode: ShadowFilterMode,
+    ) -> wgpu::RenderPipeline {
+        let preprocessed = cache
+            .get_or_preprocess(
+                &shader_library(),
+                "shadow_depth.wgsl",
+                include_str!("shaders/shadow_depth.wgsl"),
+                &shadow_feature_flags(filter_mode),
+            )
+            .expect("shadow_depth.wgsl failed to preprocess");
+
+        // wgpu validates the flattened source and reports naga errors by
+        // line number in *this* string, not the original WGSL files, so a
+        // failure here is otherwise unreadable -- dump the source annotated
+        // back to its origin via the preprocessor's source map to debug it.
+        if std::env::var_os("BLACKJACK_DUMP_SHADER_SOURCE_MAP").is_some() {
+            eprintln!(
+                "{}",
+                annotate_with_source_map(&preprocessed.source, &preprocessed.source_map)
+            );
+        }
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shadow_depth.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(preprocessed.source.as_str().into()),
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_map_pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<[f32; 3]>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x3],
+                }],
+            },
+            primitive: wgpu::PrimitiveState {
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: None,
+            multiview: None,
+        })
+    }
+
+    /// Rebuilds the depth pipeline for a new filter mode, reusing the cached
+    /// flattened shader for modes seen before. A no-op if `filter_mode`
+    /// hasn't changed.
+    pub fn set_filter_mode(&mut self, device: &wgpu::Device, filter_mode: ShadowFilterMode) {
+        if self.settings.filter_mode == filter_mode {
+            return;
+        }
+        self.settings.filter_mode = filter_mode;
+        self.pipeline = Self::build_pipeline(
+            device,
+            &self.pipeline_layout,
+            &mut self.shader_cache,
+            filter_mode,
+        );
+    }
+
+    fn make_depth_texture(device: &wgpu::Device, resolution: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow_map_depth_texture"),
+            size: wgpu::Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+    }
+
+    /// Recreates the depth texture when the resolution setting changes.
+    pub fn resize(&mut self, device: &wgpu::Device, resolution: u32) {
+        if self.settings.resolution != resolution {
+            self.settings.resolution = resolution;
+            self.depth_texture = Self::make_depth_texture(device, resolution);
+            self.depth_view = self
+                .depth_texture
+                .create_view(&wgpu::TextureViewDescriptor::default());
+            self.bind_group = Self::make_bind_group(
+                device,
+                &self.bind_group_layout,
+                &self.depth_view,
+                &self.comparison_sampler,
+                &self.unfiltered_sampler,
+                &self.light_matrix_buffer,
+            );
+        }
+    }
+
+    /// Computes the light's view-projection matrix so the whole scene
+    /// bounding sphere is captured, stores it for the subsequent depth
+    /// pass, and uploads it to `light_matrix_buffer` so the face routine's
+    /// `project_to_light_space` reads the same matrix.
+    pub fn update_light_matrix(
+        &mut self,
+        queue: &wgpu::Queue,
+        light_direction: glam::Vec3,
+        scene_radius: f32,
+    ) {
+        let direction = light_direction.normalize();
+        let eye = direction * -scene_radius * 2.0;
+        let view = glam::Mat4::look_at_rh(eye, glam::Vec3::ZERO, glam::Vec3::Y);
+        let proj = glam::Mat4::orthographic_rh(
+            -scene_radius,
+            scene_radius,
+            -scene_radius,
+            scene_radius,
+            0.01,
+            scene_radius * 4.0,
+        );
+        self.light_view_proj = proj * view;
+        queue.write_buffer(
+            &self.light_matrix_buffer,
+            0,
+            bytemuck::cast_slice(&[self.light_view_proj]),
+        );
+    }
+
+    pub fn light_view_proj(&self) -> glam::Mat4 {
+        self.light_view_proj
+    }
+
+    /// Renders `meshes` (position buffers only; shadows don't need normals
+    /// or UVs) into the shadow map, depth-only.
+    pub fn render_depth_pass<'a>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        meshes: impl Iterator<Item = &'a wgpu::Buffer>,
+        vertex_counts: impl Iterator<Item = u32>,
+    ) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("shadow_map_depth_pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_push_constants(
+            wgpu::ShaderStages::VERTEX,
+            0,
+            bytemuck::cast_slice(&[self.light_view_proj]),
+        );
+        for (buffer, count) in meshes.zip(vertex_counts) {
+            rpass.set_vertex_buffer(0, buffer.slice(..));
+            rpass.draw(0..count, 0..1);
+        }
+    }
+
+    /// Convenience wrapper around `render_depth_pass` for the common case
+    /// of a single mesh's position buffer, submitted on its own command
+    /// buffer rather than threaded through the caller's frame graph.
+    pub fn render_depth_pass_immediate(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        positions: &[glam::Vec3],
+    ) {
+        use wgpu::util::DeviceExt;
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("shadow_map_depth_pass_vertices"),
+            contents: bytemuck::cast_slice(positions),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("shadow_map_depth_pass_encoder"),
+        });
+        self.render_depth_pass(
+            &mut encoder,
+            std::iter::once(&vertex_buffer),
+            std::iter::once(positions.len() as u32),
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    pub fn comparison_sampler(&self) -> &wgpu::Sampler {
+        &self.comparison_sampler
+    }
+
+    pub fn unfiltered_sampler(&self) -> &wgpu::Sampler {
+        &self.unfiltered_sampler
+    }
+
+    pub fn depth_view(&self) -> &wgpu::TextureView {
+        &self.depth_view
+    }
+}
+
+/// Registers the shared lighting/shadow virtual library so any routine's
+/// WGSL can `#include "lib/lighting.wgsl"` or `#include "lib/shadow.wgsl"`
+/// instead of copy-pasting this module's sampling functions.
+pub fn shader_library() -> ShaderLibrary {
+    let mut library = ShaderLibrary::new();
+    library.register(
+        "lib/lighting.wgsl",
+        include_str!("shaders/lib/lighting.wgsl"),
+    );
+    library.register("lib/shadow.wgsl", include_str!("shaders/lib/shadow.wgsl"));
+    library
+}
+
+/// Feature flags a routine should pass to the preprocessor to pull in the
+/// shadow-sampling path with this routine's chosen filter, matching
+/// `ShadowFilterMode`.
+pub fn shadow_feature_flags(filter_mode: ShadowFilterMode) -> HashSet<&'static str> {
+    let mut flags = HashSet::from(["SHADOWS"]);
+    match filter_mode {
+        ShadowFilterMode::Hardware => {}
+        ShadowFilterMode::Pcf => {
+            flags.insert("SHADOW_FILTER_PCF");
+        }
+        ShadowFilterMode::Pcss => {
+            flags.insert("SHADOW_FILTER_PCSS");
+        }
+    }
+    flags
+}